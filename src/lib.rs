@@ -1,12 +1,25 @@
 // inspired by http://brettbeauregard.com/blog/2011/04/improving-the-beginners-pid-introduction/
 
+// unlike `Controller`, `ControllerBuilder` can derive `Deserialize` directly:
+// it is inert data until `build()` is called, and `build()` already rejects
+// zero gains and inverted limit ranges, so there is no invariant to smuggle
+// past a snapshot type here
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ControllerBuilder {
     pub kp: f64,
     pub ki: f64,
     pub kd: f64,
     pub target: f64,
     pub max_output_value: f64,
+    pub output_min: f64,
+    pub integral_min: f64,
+    pub integral_max: f64,
+    pub p_limit: f64,
+    pub i_limit: f64,
+    pub d_limit: f64,
     pub derivative_on_measurement: bool,
+    pub velocity_form: bool,
+    pub derivative_filter_tau: f64,
 }
 
 impl ControllerBuilder {
@@ -16,8 +29,16 @@ impl ControllerBuilder {
             ki: 0.0,
             kd: 0.0,
             target,
-            max_output_value: std::f64::MAX,
+            max_output_value: f64::MAX,
+            output_min: f64::MIN,
+            integral_min: f64::MIN,
+            integral_max: f64::MAX,
+            p_limit: f64::MAX,
+            i_limit: f64::MAX,
+            d_limit: f64::MAX,
             derivative_on_measurement: false,
+            velocity_form: false,
+            derivative_filter_tau: 0.0,
         }
     }
 
@@ -46,35 +67,227 @@ impl ControllerBuilder {
         self
     }
 
+    pub fn with_integral_limits(mut self, integral_min: f64, integral_max: f64) -> Self {
+        self.integral_min = integral_min;
+        self.integral_max = integral_max;
+        self
+    }
+
+    pub fn with_output_limits(mut self, output_min: f64, output_max: f64) -> Self {
+        self.output_min = output_min;
+        self.max_output_value = output_max;
+        self
+    }
+
+    pub fn with_p_limit(mut self, p_limit: f64) -> Self {
+        self.p_limit = p_limit;
+        self
+    }
+
+    pub fn with_i_limit(mut self, i_limit: f64) -> Self {
+        self.i_limit = i_limit;
+        self
+    }
+
+    pub fn with_d_limit(mut self, d_limit: f64) -> Self {
+        self.d_limit = d_limit;
+        self
+    }
+
+    /// Switches `compute` to the discrete incremental (velocity) recurrence
+    /// instead of the default positional form. In this mode `ki` and `kd`
+    /// are expected to already fold in the sample time, since the
+    /// recurrence does not multiply them by `time_elapsed` at runtime.
+    pub fn with_velocity_form(mut self) -> Self {
+        self.velocity_form = true;
+        self
+    }
+
+    /// Applies a first-order low-pass filter of time constant `tau` to the
+    /// derivative term, smoothing out noisy measurements. A `tau` of `0.0`
+    /// (the default) leaves the derivative unfiltered.
+    pub fn with_derivative_filter(mut self, tau: f64) -> Self {
+        self.derivative_filter_tau = tau;
+        self
+    }
+
     pub fn build(&self) -> Result<Controller, String> {
-        if self.kp == 0.0 && self.ki == 0.0 && self.kd == 0.0 {
-            Err("All gains cannot be zero at the same time".to_string())
-        } else {
-            Ok(Controller::new(
-                self.kp,
-                self.ki,
-                self.kd,
-                self.target,
-                self.derivative_on_measurement,
-                self.max_output_value,
-            ))
-        }
+        validate_gains_and_limits(
+            self.kp,
+            self.ki,
+            self.kd,
+            self.output_min,
+            self.max_output_value,
+            self.integral_min,
+            self.integral_max,
+            self.p_limit,
+            self.i_limit,
+            self.d_limit,
+            self.derivative_filter_tau,
+        )?;
+        Ok(Controller::new(
+            self.kp,
+            self.ki,
+            self.kd,
+            self.target,
+            self.derivative_on_measurement,
+            self.max_output_value,
+            self.output_min,
+            self.integral_min,
+            self.integral_max,
+            self.p_limit,
+            self.i_limit,
+            self.d_limit,
+            self.velocity_form,
+            self.derivative_filter_tau,
+        ))
+    }
+}
+
+// shared by `ControllerBuilder::build` and `Controller`'s deserializing
+// `TryFrom` so untrusted data can't bypass the same invariants
+#[allow(clippy::too_many_arguments)]
+fn validate_gains_and_limits(
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    output_min: f64,
+    max_output_value: f64,
+    integral_min: f64,
+    integral_max: f64,
+    p_limit: f64,
+    i_limit: f64,
+    d_limit: f64,
+    derivative_filter_tau: f64,
+) -> Result<(), String> {
+    if kp == 0.0 && ki == 0.0 && kd == 0.0 {
+        Err("All gains cannot be zero at the same time".to_string())
+    } else if output_min > max_output_value {
+        Err("output_min cannot be greater than max_output_value".to_string())
+    } else if integral_min > integral_max {
+        Err("integral_min cannot be greater than integral_max".to_string())
+    } else if p_limit < 0.0 || i_limit < 0.0 || d_limit < 0.0 {
+        Err("p_limit, i_limit and d_limit cannot be negative".to_string())
+    } else if derivative_filter_tau < 0.0 {
+        Err("derivative_filter_tau cannot be negative".to_string())
+    } else {
+        Ok(())
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ControllerSnapshot"))]
 pub struct Controller {
     pub kp: f64,
     pub ki: f64,
     pub kd: f64,
     pub target: f64,
     pub max_output_value: f64,
+    pub output_min: f64,
+    pub integral_min: f64,
+    pub integral_max: f64,
+    pub p_limit: f64,
+    pub i_limit: f64,
+    pub d_limit: f64,
     pub derivative_on_measurement: bool,
+    pub velocity_form: bool,
+    pub derivative_filter_tau: f64,
     pub sum_error: f64,
     pub last_error: f64,
     pub last_input: f64,
+    pub last_output: f64,
+    pub last_d_filtered: f64,
+    pub x1: f64,
+    pub x2: f64,
+    pub u1: f64,
+    pub y1: f64,
+    // `last_output` starts at 0.0 before the first call to `compute`, which is
+    // indistinguishable from a real output pinned at a boundary of 0.0 (e.g. a
+    // 0-100% duty cycle); this tracks whether that sentinel is real
+    pub has_run: bool,
+}
+
+// deserializing untrusted data must not be able to bypass the invariants
+// `ControllerBuilder::build` enforces, so `Controller` deserializes via this
+// snapshot plus a validating `TryFrom` instead of deriving directly
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct ControllerSnapshot {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    target: f64,
+    max_output_value: f64,
+    output_min: f64,
+    integral_min: f64,
+    integral_max: f64,
+    p_limit: f64,
+    i_limit: f64,
+    d_limit: f64,
+    derivative_on_measurement: bool,
+    velocity_form: bool,
+    derivative_filter_tau: f64,
+    sum_error: f64,
+    last_error: f64,
+    last_input: f64,
+    last_output: f64,
+    last_d_filtered: f64,
+    x1: f64,
+    x2: f64,
+    u1: f64,
+    y1: f64,
+    has_run: bool,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<ControllerSnapshot> for Controller {
+    type Error = String;
+
+    fn try_from(snapshot: ControllerSnapshot) -> Result<Self, Self::Error> {
+        validate_gains_and_limits(
+            snapshot.kp,
+            snapshot.ki,
+            snapshot.kd,
+            snapshot.output_min,
+            snapshot.max_output_value,
+            snapshot.integral_min,
+            snapshot.integral_max,
+            snapshot.p_limit,
+            snapshot.i_limit,
+            snapshot.d_limit,
+            snapshot.derivative_filter_tau,
+        )?;
+        Ok(Controller {
+            kp: snapshot.kp,
+            ki: snapshot.ki,
+            kd: snapshot.kd,
+            target: snapshot.target,
+            max_output_value: snapshot.max_output_value,
+            output_min: snapshot.output_min,
+            integral_min: snapshot.integral_min,
+            integral_max: snapshot.integral_max,
+            p_limit: snapshot.p_limit,
+            i_limit: snapshot.i_limit,
+            d_limit: snapshot.d_limit,
+            derivative_on_measurement: snapshot.derivative_on_measurement,
+            velocity_form: snapshot.velocity_form,
+            derivative_filter_tau: snapshot.derivative_filter_tau,
+            sum_error: snapshot.sum_error,
+            last_error: snapshot.last_error,
+            last_input: snapshot.last_input,
+            last_output: snapshot.last_output,
+            last_d_filtered: snapshot.last_d_filtered,
+            x1: snapshot.x1,
+            x2: snapshot.x2,
+            u1: snapshot.u1,
+            y1: snapshot.y1,
+            has_run: snapshot.has_run,
+        })
+    }
 }
 
 impl Controller {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         kp: f64,
         ki: f64,
@@ -82,6 +295,14 @@ impl Controller {
         target: f64,
         derivative_on_measurement: bool,
         max_output_value: f64,
+        output_min: f64,
+        integral_min: f64,
+        integral_max: f64,
+        p_limit: f64,
+        i_limit: f64,
+        d_limit: f64,
+        velocity_form: bool,
+        derivative_filter_tau: f64,
     ) -> Controller {
         Controller {
             kp,
@@ -90,9 +311,24 @@ impl Controller {
             target,
             derivative_on_measurement,
             max_output_value,
+            output_min,
+            integral_min,
+            integral_max,
+            p_limit,
+            i_limit,
+            d_limit,
+            velocity_form,
+            derivative_filter_tau,
             sum_error: 0.0,
             last_error: 0.0,
             last_input: 0.0,
+            last_output: 0.0,
+            last_d_filtered: 0.0,
+            x1: 0.0,
+            x2: 0.0,
+            u1: 0.0,
+            y1: 0.0,
+            has_run: false,
         }
     }
 
@@ -101,45 +337,110 @@ impl Controller {
         self
     }
 
+    /// Returns the current `(kp, ki, kd)` gains.
+    pub fn gains(&self) -> (f64, f64, f64) {
+        (self.kp, self.ki, self.kd)
+    }
+
+    /// Hot-swaps the gains in place without touching any accumulated state
+    /// (`sum_error`, `last_*`). `sum_error` is not rescaled, so in the
+    /// positional form a change of `ki` changes the integral term of the
+    /// next output by `(new_ki - old_ki) * sum_error`. The accumulator state
+    /// itself is readable directly through the controller's public fields
+    /// for persistence or inspection.
+    pub fn set_gains(&mut self, kp: f64, ki: f64, kd: f64) {
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+    }
+
     pub fn reset(mut self) -> Self {
         self.last_error = 0.0;
         self.last_input = 0.0;
         self.sum_error = 0.0;
+        self.last_output = 0.0;
+        self.last_d_filtered = 0.0;
+        self.x1 = 0.0;
+        self.x2 = 0.0;
+        self.u1 = 0.0;
+        self.y1 = 0.0;
+        self.has_run = false;
         self
     }
 
     pub fn compute(&mut self, input: f64, time_elapsed: f64) -> f64 {
+        if self.velocity_form {
+            return self.compute_velocity(input);
+        }
         let current_error = self.target - input;
-        let current_sum_error = self.sum_error + current_error * time_elapsed as f64;
         let derivative_error = if time_elapsed == 0.0 {
             0.0 // first round
-        } else if self.derivative_on_measurement {
-            (input - self.last_input) / time_elapsed as f64
         } else {
-            (current_error - self.last_error) / time_elapsed as f64
+            let raw_derivative = if self.derivative_on_measurement {
+                (input - self.last_input) / time_elapsed
+            } else {
+                (current_error - self.last_error) / time_elapsed
+            };
+            let alpha = time_elapsed / (self.derivative_filter_tau + time_elapsed);
+            let filtered = alpha * raw_derivative + (1.0 - alpha) * self.last_d_filtered;
+            self.last_d_filtered = filtered;
+            filtered
         };
 
+        // conditional integration: only keep accumulating while the previous
+        // output was not saturated, then clamp the accumulator itself to
+        // avoid windup while it is free to move; before the first call there
+        // is no previous output to be saturated, regardless of where
+        // `last_output`'s 0.0 sentinel falls relative to the output bounds
+        let was_saturated = self.has_run
+            && (self.last_output <= self.output_min || self.last_output >= self.max_output_value);
+        if !was_saturated {
+            let current_sum_error = self.sum_error + current_error * time_elapsed;
+            self.sum_error = current_sum_error.clamp(self.integral_min, self.integral_max);
+        }
+
         // update internal state
         self.last_error = current_error;
         self.last_input = input;
-        self.sum_error = current_sum_error;
 
-        let p_term = self.kp * current_error;
-        let i_term = self.ki * current_sum_error;
-        let d_term = self.kd * derivative_error;
+        let p_term = (self.kp * current_error).clamp(-self.p_limit, self.p_limit);
+        let i_term = (self.ki * self.sum_error).clamp(-self.i_limit, self.i_limit);
+        let d_term = (self.kd * derivative_error).clamp(-self.d_limit, self.d_limit);
 
-        let output = p_term + i_term + d_term;
-        if output > self.max_output_value {
-            self.max_output_value
-        } else {
-            output
-        }
+        let output = (p_term + i_term + d_term).clamp(self.output_min, self.max_output_value);
+        self.last_output = output;
+        self.has_run = true;
+        output
+    }
+
+    // discrete incremental (velocity) recurrence, an alternative to the
+    // positional form above; ki/kd are expected pre-scaled for sample time
+    fn compute_velocity(&mut self, input: f64) -> f64 {
+        let x0 = input;
+        let u0 = self.target;
+
+        let y0 = self.y1 - self.ki * u0 + x0 * (self.kp + self.ki + self.kd)
+            - self.x1 * (self.kp + 2.0 * self.kd)
+            + self.x2 * self.kd
+            + self.kp * (u0 - self.u1);
+        let y0 = y0.clamp(self.output_min, self.max_output_value);
+
+        self.x2 = self.x1;
+        self.x1 = x0;
+        self.u1 = u0;
+        self.y1 = y0;
+        self.last_output = y0;
+        self.has_run = true;
+
+        y0
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::ControllerBuilder;
+    #[cfg(feature = "serde")]
+    use crate::Controller;
 
     #[test]
     fn only_proportional() {
@@ -216,4 +517,219 @@ mod tests {
         let output_2 = controller.compute(1.0, 100.0);
         assert_eq!(2.0, output_2);
     }
+
+    #[test]
+    fn integral_stops_accumulating_once_output_saturates() {
+        let mut controller = ControllerBuilder::new_with_target(1000.0)
+            .with_max_output_value(2.0)
+            .with_i_gain(1.0)
+            .build()
+            .unwrap();
+
+        // output saturates immediately, so the integral must not keep growing
+        let _ = controller.compute(1.0, 100.0);
+        let sum_error_after_first = controller.sum_error;
+        let _ = controller.compute(1.0, 100.0);
+        assert_eq!(sum_error_after_first, controller.sum_error);
+    }
+
+    #[test]
+    fn integral_accumulates_even_when_output_bound_is_zero() {
+        // output_min == 0.0 used to be mistaken for an already-saturated
+        // previous output on the very first call, permanently freezing the
+        // integral at 0.0
+        let mut controller = ControllerBuilder::new_with_target(10.0)
+            .with_output_limits(0.0, 100.0)
+            .with_i_gain(1.0)
+            .build()
+            .unwrap();
+
+        let _ = controller.compute(5.0, 1.0);
+        assert_eq!(5.0, controller.sum_error);
+        let _ = controller.compute(5.0, 1.0);
+        assert_eq!(10.0, controller.sum_error);
+    }
+
+    #[test]
+    fn integral_is_clamped_to_its_configured_bounds() {
+        let mut controller = ControllerBuilder::new_with_target(1000.0)
+            .with_i_gain(1.0)
+            .with_integral_limits(-10.0, 10.0)
+            .build()
+            .unwrap();
+
+        let _ = controller.compute(1.0, 100.0);
+        assert_eq!(10.0, controller.sum_error);
+    }
+
+    #[test]
+    fn with_output_limits_clamps_negative_output() {
+        let mut controller = ControllerBuilder::new_with_target(-1000.0)
+            .with_output_limits(-2.0, 2.0)
+            .with_p_gain(10.0)
+            .build()
+            .unwrap();
+
+        // output is driven very negative, but must not go below output_min
+        let output_1 = controller.compute(1.0, 100.0);
+        assert_eq!(-2.0, output_1);
+    }
+
+    #[test]
+    fn with_p_limit_caps_proportional_contribution() {
+        let mut controller = ControllerBuilder::new_with_target(1000.0)
+            .with_p_gain(10.0)
+            .with_p_limit(5.0)
+            .build()
+            .unwrap();
+
+        // the proportional term alone would be 9990, capped to 5.0
+        let output_1 = controller.compute(1.0, 100.0);
+        assert_eq!(5.0, output_1);
+    }
+
+    #[test]
+    fn build_rejects_inverted_output_limits() {
+        let result = ControllerBuilder::new_with_target(10.0)
+            .with_p_gain(1.0)
+            .with_output_limits(5.0, -5.0)
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn build_rejects_inverted_integral_limits() {
+        let result = ControllerBuilder::new_with_target(10.0)
+            .with_p_gain(1.0)
+            .with_integral_limits(5.0, -5.0)
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn build_rejects_negative_term_limits() {
+        let result = ControllerBuilder::new_with_target(10.0)
+            .with_p_gain(1.0)
+            .with_p_limit(-5.0)
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn build_rejects_negative_derivative_filter_tau() {
+        let result = ControllerBuilder::new_with_target(10.0)
+            .with_d_gain(1.0)
+            .with_derivative_filter(-0.5)
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn velocity_form_matches_manual_recurrence() {
+        let mut controller = ControllerBuilder::new_with_target(10.0)
+            .with_p_gain(1.0)
+            .with_i_gain(0.5)
+            .with_d_gain(0.25)
+            .with_velocity_form()
+            .build()
+            .unwrap();
+
+        // first call: x1, x2 and u1 are still zero, so the target jump still
+        // shows up through the kp*(u0 - u1) term
+        let output_1 = controller.compute(2.0, 1.0);
+        assert_eq!(
+            0.0 - 0.5 * 10.0 + 2.0 * (1.0 + 0.5 + 0.25) + 1.0 * (10.0 - 0.0),
+            output_1
+        );
+
+        // second call now folds in the previous measurement via x1; the
+        // target did not change, so the kp*(u0 - u1) term vanishes
+        let x1 = 2.0;
+        let output_2 = controller.compute(5.0, 1.0);
+        let expected = output_1 - 0.5 * 10.0 + 5.0 * (1.0 + 0.5 + 0.25) - x1 * (1.0 + 2.0 * 0.25);
+        assert_eq!(expected, output_2);
+    }
+
+    #[test]
+    fn derivative_filter_smooths_a_noisy_spike() {
+        let mut unfiltered = ControllerBuilder::new_with_target(10.0)
+            .with_d_gain(1.0)
+            .build()
+            .unwrap();
+        let mut filtered = ControllerBuilder::new_with_target(10.0)
+            .with_d_gain(1.0)
+            .with_derivative_filter(1.0)
+            .build()
+            .unwrap();
+
+        let _ = unfiltered.compute(5.0, 1.0);
+        let _ = filtered.compute(5.0, 1.0);
+
+        // a single noisy spike swings the raw derivative hard, the filtered
+        // one should move less in the same direction
+        let noisy_output = unfiltered.compute(9.0, 1.0);
+        let filtered_output = filtered.compute(9.0, 1.0);
+        assert!(filtered_output.abs() < noisy_output.abs());
+    }
+
+    #[test]
+    fn derivative_filter_with_zero_tau_matches_unfiltered() {
+        let mut controller = ControllerBuilder::new_with_target(10.0)
+            .with_d_gain(0.5)
+            .with_derivative_filter(0.0)
+            .build()
+            .unwrap();
+
+        let output_1 = controller.compute(9.0, 1.0);
+        assert_eq!(0.5, output_1);
+        let output_2 = controller.compute(7.0, 1.0);
+        assert_eq!(1.0, output_2);
+    }
+
+    #[test]
+    fn set_gains_retunes_without_resetting_state() {
+        let mut controller = ControllerBuilder::new_with_target(10.0)
+            .with_i_gain(0.5)
+            .build()
+            .unwrap();
+
+        let _ = controller.compute(8.0, 1.0);
+        assert_eq!((0.0, 0.5, 0.0), controller.gains());
+
+        controller.set_gains(0.0, 1.0, 0.0);
+        assert_eq!((0.0, 1.0, 0.0), controller.gains());
+        // the accumulated error from before the retune is preserved
+        assert_eq!(2.0, controller.sum_error);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserializing_an_all_zero_gain_controller_is_rejected() {
+        let controller = ControllerBuilder::new_with_target(10.0)
+            .with_p_gain(1.0)
+            .build()
+            .unwrap();
+        let mut json = serde_json::to_value(&controller).unwrap();
+        json["kp"] = serde_json::json!(0.0);
+        json["ki"] = serde_json::json!(0.0);
+        json["kd"] = serde_json::json!(0.0);
+
+        let result: Result<Controller, _> = serde_json::from_value(json);
+        assert!(result.is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserializing_an_inverted_output_range_is_rejected() {
+        let controller = ControllerBuilder::new_with_target(10.0)
+            .with_p_gain(1.0)
+            .build()
+            .unwrap();
+        let mut json = serde_json::to_value(&controller).unwrap();
+        json["output_min"] = serde_json::json!(5.0);
+        json["max_output_value"] = serde_json::json!(-5.0);
+
+        let result: Result<Controller, _> = serde_json::from_value(json);
+        assert!(result.is_err());
+    }
 }